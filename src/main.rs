@@ -1,4 +1,5 @@
 use {
+    libc::{isatty, STDOUT_FILENO},
     nix::{
         sys::signal::{kill, Signal::SIGTERM},
         unistd::Pid,
@@ -16,8 +17,69 @@ use {
 type CmdOut = BufReader<ChildStdout>;
 type Res<T> = io::Result<T>;
 
-pub struct Color(Option<String>);
-pub struct DrawColor<'a, D: Display>(&'a Color, D);
+pub struct Color(Option<ColorValue>);
+#[derive(Clone, Copy)]
+pub struct Style<'a> {
+    fg: &'a Color,
+    bg: &'a Color,
+    underline: &'a Color,
+}
+pub struct DrawStyle<'a, D: Display>(Style<'a>, D, Output, bool);
+
+#[derive(Clone, Copy)]
+pub enum Output {
+    Lemonbar,
+    Ansi,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "lemonbar" => Ok(Self::Lemonbar),
+            "ansi" => Ok(Self::Ansi),
+            _ => Err(format!("Invalid output: {}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum When {
+    Always,
+    Auto,
+    Never,
+}
+
+impl FromStr for When {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Self::Always),
+            "auto" => Ok(Self::Auto),
+            "never" => Ok(Self::Never),
+            _ => Err(format!("Invalid color: {}", s)),
+        }
+    }
+}
+
+impl When {
+    /// Resolve to an effective colorize flag for the chosen `output`.
+    ///
+    /// Lemonbar markup is consumed by lemonbar, not a terminal, so piping
+    /// into it is the expected (non-tty) use case: `auto` keeps colorizing
+    /// there. For `ansi`, `auto` only colorizes when stdout is a tty, since
+    /// that's the backend meant for terminals/tmux.
+    fn resolve(self, output: Output) -> bool {
+        match (self, output) {
+            (Self::Always, _) => true,
+            (Self::Never, _) => false,
+            (Self::Auto, Output::Lemonbar) => true,
+            (Self::Auto, Output::Ansi) => unsafe { isatty(STDOUT_FILENO) != 0 },
+        }
+    }
+}
 
 #[derive(StructOpt)]
 /// Bspwm status watcher
@@ -25,18 +87,119 @@ pub struct Colors {
     #[structopt(long = "color-free", name = "COLOR_FREE", default_value = "")]
     /// A color for free desktop
     free: Color,
+    #[structopt(long = "bg-free", name = "BG_FREE", default_value = "")]
+    /// A background color for free desktop
+    bg_free: Color,
+    #[structopt(long = "underline-free", name = "UNDERLINE_FREE", default_value = "")]
+    /// An underline color for free desktop
+    underline_free: Color,
     #[structopt(long = "color-monitor", name = "COLOR_MONITOR", default_value = "")]
     /// A color for monitor
     monitor: Color,
+    #[structopt(long = "bg-monitor", name = "BG_MONITOR", default_value = "")]
+    /// A background color for monitor
+    bg_monitor: Color,
+    #[structopt(
+        long = "underline-monitor",
+        name = "UNDERLINE_MONITOR",
+        default_value = ""
+    )]
+    /// An underline color for monitor
+    underline_monitor: Color,
     #[structopt(long = "color-occupied", name = "COLOR_OCCUPIED", default_value = "")]
     /// A color for occupied desktop
     occupied: Color,
+    #[structopt(long = "bg-occupied", name = "BG_OCCUPIED", default_value = "")]
+    /// A background color for occupied desktop
+    bg_occupied: Color,
+    #[structopt(
+        long = "underline-occupied",
+        name = "UNDERLINE_OCCUPIED",
+        default_value = ""
+    )]
+    /// An underline color for occupied desktop
+    underline_occupied: Color,
     #[structopt(long = "color-urgent", name = "COLOR_URGENT", default_value = "")]
     /// A color for urgent desktop
     urgent: Color,
+    #[structopt(long = "bg-urgent", name = "BG_URGENT", default_value = "")]
+    /// A background color for urgent desktop
+    bg_urgent: Color,
+    #[structopt(
+        long = "underline-urgent",
+        name = "UNDERLINE_URGENT",
+        default_value = ""
+    )]
+    /// An underline color for urgent desktop
+    underline_urgent: Color,
     #[structopt(long = "color-state", name = "COLOR_STATE", default_value = "")]
     /// A color for window state
     state: Color,
+    #[structopt(long = "bg-state", name = "BG_STATE", default_value = "")]
+    /// A background color for window state
+    bg_state: Color,
+    #[structopt(long = "underline-state", name = "UNDERLINE_STATE", default_value = "")]
+    /// An underline color for window state
+    underline_state: Color,
+    #[structopt(
+        long = "output",
+        name = "OUTPUT",
+        default_value = "lemonbar",
+        possible_values = &["lemonbar", "ansi"]
+    )]
+    /// Output format for color escapes
+    output: Output,
+    #[structopt(
+        long = "color",
+        name = "WHEN",
+        default_value = "auto",
+        possible_values = &["always", "auto", "never"]
+    )]
+    /// Whether to emit color escapes: always, auto or never (auto colorizes
+    /// lemonbar output always, ansi output only on a tty)
+    color: When,
+}
+
+impl Colors {
+    fn free_style(&self) -> Style<'_> {
+        Style {
+            fg: &self.free,
+            bg: &self.bg_free,
+            underline: &self.underline_free,
+        }
+    }
+
+    fn monitor_style(&self) -> Style<'_> {
+        Style {
+            fg: &self.monitor,
+            bg: &self.bg_monitor,
+            underline: &self.underline_monitor,
+        }
+    }
+
+    fn occupied_style(&self) -> Style<'_> {
+        Style {
+            fg: &self.occupied,
+            bg: &self.bg_occupied,
+            underline: &self.underline_occupied,
+        }
+    }
+
+    fn urgent_style(&self) -> Style<'_> {
+        Style {
+            fg: &self.urgent,
+            bg: &self.bg_urgent,
+            underline: &self.underline_urgent,
+        }
+    }
+
+    fn state_style(&self) -> Style<'_> {
+        Style {
+            fg: &self.state,
+            bg: &self.bg_state,
+            underline: &self.underline_state,
+        }
+    }
 }
 
 pub const BSPWM_CMD: &'static [&'static str] = &["bspc", "subscribe"];
@@ -52,6 +215,7 @@ fn run() -> Res<()> {
     let out = stdout();
     let mut out = BufWriter::new(out.lock());
     let colors = Colors::from_args();
+    let colorize = colors.color.resolve(colors.output);
     let (child, mut child_stdout) = command_stdout(BSPWM_CMD)?;
     let mut buf = String::new();
     let mut new_buf = String::new();
@@ -69,7 +233,7 @@ fn run() -> Res<()> {
             Ok(_) => {
                 new_buf.pop();
                 if new_buf != buf {
-                    print_bspwm(&colors, &mut out, &new_buf)?;
+                    print_bspwm(&colors, colorize, &mut out, &new_buf)?;
                 }
                 buf.clear();
                 std::mem::swap(&mut new_buf, &mut buf);
@@ -80,7 +244,7 @@ fn run() -> Res<()> {
     Ok(())
 }
 
-fn print_bspwm(c: &Colors, mut out: impl Write, bspwm: &str) -> Res<()> {
+fn print_bspwm(c: &Colors, colorize: bool, mut out: impl Write, bspwm: &str) -> Res<()> {
     fn split(s: &str) -> Option<(char, &str)> {
         if s.len() > 1 {
             Some((s.as_bytes()[0] as char, &s[1..]))
@@ -91,15 +255,39 @@ fn print_bspwm(c: &Colors, mut out: impl Write, bspwm: &str) -> Res<()> {
 
     for (start, name) in bspwm[1..].split(':').filter_map(split) {
         match start {
-            'm' => write!(out, " {}  ", c.monitor.draw(name))?,
-            'M' => write!(out, "-{}- ", c.monitor.draw(name))?,
-            'f' => write!(out, " {}  ", c.free.draw(name))?,
-            'F' => write!(out, "-{}- ", c.free.draw(name))?,
-            'o' => write!(out, " {}  ", c.occupied.draw(name))?,
-            'O' => write!(out, "-{}- ", c.occupied.draw(name))?,
-            'u' => write!(out, " {}  ", c.urgent.draw(name))?,
-            'U' => write!(out, "-{}- ", c.urgent.draw(name))?,
-            'L' | 'T' | 'G' => write!(out, " {}", c.state.draw(name))?,
+            'm' => write!(
+                out,
+                " {}  ",
+                c.monitor_style().draw(name, c.output, colorize)
+            )?,
+            'M' => write!(
+                out,
+                "-{}- ",
+                c.monitor_style().draw(name, c.output, colorize)
+            )?,
+            'f' => write!(out, " {}  ", c.free_style().draw(name, c.output, colorize))?,
+            'F' => write!(out, "-{}- ", c.free_style().draw(name, c.output, colorize))?,
+            'o' => write!(
+                out,
+                " {}  ",
+                c.occupied_style().draw(name, c.output, colorize)
+            )?,
+            'O' => write!(
+                out,
+                "-{}- ",
+                c.occupied_style().draw(name, c.output, colorize)
+            )?,
+            'u' => write!(
+                out,
+                " {}  ",
+                c.urgent_style().draw(name, c.output, colorize)
+            )?,
+            'U' => write!(
+                out,
+                "-{}- ",
+                c.urgent_style().draw(name, c.output, colorize)
+            )?,
+            'L' | 'T' | 'G' => write!(out, " {}", c.state_style().draw(name, c.output, colorize))?,
             _ => continue,
         }
     }
@@ -121,9 +309,93 @@ fn command_stdout(command: &[&str]) -> Res<(Child, CmdOut)> {
     Ok((child, BufReader::new(stdout)))
 }
 
+enum ColorValue {
+    Hex(String),
+    Index(u8),
+}
+
 impl Color {
-    pub fn draw<D: Display>(&self, element: D) -> DrawColor<D> {
-        DrawColor(self, element)
+    fn rgb(hex: &str) -> Option<(u8, u8, u8)> {
+        let digits = hex.strip_prefix("#")?;
+        let rgb = match digits.len() {
+            6 => digits,
+            8 => &digits[2..],
+            _ => return None,
+        };
+        Some((
+            u8::from_str_radix(&rgb[0..2], 16).ok()?,
+            u8::from_str_radix(&rgb[2..4], 16).ok()?,
+            u8::from_str_radix(&rgb[4..6], 16).ok()?,
+        ))
+    }
+
+    fn xterm_256_hex(index: u8) -> String {
+        const BASE16: [(u8, u8, u8); 16] = [
+            (0, 0, 0),
+            (205, 0, 0),
+            (0, 205, 0),
+            (205, 205, 0),
+            (0, 0, 238),
+            (205, 0, 205),
+            (0, 205, 205),
+            (229, 229, 229),
+            (127, 127, 127),
+            (255, 0, 0),
+            (0, 255, 0),
+            (255, 255, 0),
+            (92, 92, 255),
+            (255, 0, 255),
+            (0, 255, 255),
+            (255, 255, 255),
+        ];
+
+        fn cube_level(v: u8) -> u8 {
+            if v == 0 {
+                0
+            } else {
+                55 + v * 40
+            }
+        }
+
+        let (r, g, b) = match index {
+            0..=15 => BASE16[index as usize],
+            16..=231 => {
+                let i = index - 16;
+                (
+                    cube_level(i / 36),
+                    cube_level((i / 6) % 6),
+                    cube_level(i % 6),
+                )
+            }
+            232..=255 => {
+                let level = 8 + (index - 232) * 10;
+                (level, level, level)
+            }
+        };
+        format!("#{:02x}{:02x}{:02x}", r, g, b)
+    }
+
+    /// The hex form of this color, resolving a palette index through the
+    /// built-in xterm-256 palette.
+    fn to_hex(&self) -> Option<String> {
+        match &self.0 {
+            None => None,
+            Some(ColorValue::Hex(h)) => Some(h.clone()),
+            Some(ColorValue::Index(i)) => Some(Self::xterm_256_hex(*i)),
+        }
+    }
+
+    /// The SGR parameters selecting this color as fg (`base` 38), bg (`base` 48)
+    /// or underline (`base` 58).
+    fn sgr(&self, base: u8) -> Option<String> {
+        match &self.0 {
+            None => None,
+            Some(ColorValue::Hex(h)) => {
+                let (r, g, b) = Self::rgb(h)?;
+                Some(format!("{};2;{};{};{}", base, r, g, b))
+            }
+            Some(ColorValue::Index(i)) => Some(format!("{};5;{}", base, i)),
+        }
     }
 }
 
@@ -132,24 +404,238 @@ impl FromStr for Color {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if s.is_empty() {
-            Ok(Self(None))
-        } else if s.len() == 7
-            && s.starts_with("#")
-            && s.chars().skip(1).all(|c| c.is_ascii_hexdigit())
-        {
-            Ok(Self(Some(s.into())))
-        } else {
-            Err(format!("Invalid hex color: {}", s))
+            return Ok(Self(None));
+        }
+
+        if s.chars().all(|c| c.is_ascii_digit()) {
+            return s
+                .parse::<u8>()
+                .map(|i| Self(Some(ColorValue::Index(i))))
+                .map_err(|_| format!("Invalid palette index: {}", s));
+        }
+
+        let digits = s
+            .strip_prefix("#")
+            .ok_or_else(|| format!("Invalid hex color: {}", s))?;
+
+        if !matches!(s.len(), 4 | 5 | 7 | 9) || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!("Invalid hex color: {}", s));
         }
+
+        let normalized = if digits.len() == 3 || digits.len() == 4 {
+            digits.chars().flat_map(|c| [c, c]).collect()
+        } else {
+            digits.to_string()
+        };
+
+        Ok(Self(Some(ColorValue::Hex(format!("#{}", normalized)))))
+    }
+}
+
+impl<'a> Style<'a> {
+    pub fn draw<D: Display>(self, element: D, output: Output, colorize: bool) -> DrawStyle<'a, D> {
+        DrawStyle(self, element, output, colorize)
     }
 }
 
-impl<'a, D: Display> Display for DrawColor<'a, D> {
+impl<'a, D: Display> Display for DrawStyle<'a, D> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if let Some(c) = &(self.0).0 {
-            write!(f, "%{{F{}}}{}%{{F-}}", c, self.1)
-        } else {
-            write!(f, "{}", self.1)
+        if !self.3 {
+            return write!(f, "{}", self.1);
+        }
+
+        let style = &self.0;
+        match self.2 {
+            Output::Lemonbar => {
+                let fg = style.fg.to_hex();
+                let bg = style.bg.to_hex();
+                let underline = style.underline.to_hex();
+
+                if let Some(c) = &fg {
+                    write!(f, "%{{F{}}}", c)?;
+                }
+                if let Some(c) = &bg {
+                    write!(f, "%{{B{}}}", c)?;
+                }
+                if let Some(c) = &underline {
+                    write!(f, "%{{+u}}%{{U{}}}", c)?;
+                }
+                write!(f, "{}", self.1)?;
+                if underline.is_some() {
+                    write!(f, "%{{-u}}")?;
+                }
+                if bg.is_some() {
+                    write!(f, "%{{B-}}")?;
+                }
+                if fg.is_some() {
+                    write!(f, "%{{F-}}")?;
+                }
+                Ok(())
+            }
+            Output::Ansi => {
+                let fg = style.fg.sgr(38);
+                let bg = style.bg.sgr(48);
+                let underline = style.underline.sgr(58);
+
+                if let Some(code) = &fg {
+                    write!(f, "\x1b[{}m", code)?;
+                }
+                if let Some(code) = &bg {
+                    write!(f, "\x1b[{}m", code)?;
+                }
+                if let Some(code) = &underline {
+                    write!(f, "\x1b[4m\x1b[{}m", code)?;
+                }
+                write!(f, "{}", self.1)?;
+                if underline.is_some() {
+                    write!(f, "\x1b[59m\x1b[24m")?;
+                }
+                if bg.is_some() {
+                    write!(f, "\x1b[49m")?;
+                }
+                if fg.is_some() {
+                    write!(f, "\x1b[39m")?;
+                }
+                Ok(())
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_shorthand_rgb() {
+        let c: Color = "#abc".parse().unwrap();
+        assert_eq!(c.to_hex().as_deref(), Some("#aabbcc"));
+    }
+
+    #[test]
+    fn parses_shorthand_argb() {
+        let c: Color = "#f0ab".parse().unwrap();
+        assert_eq!(c.to_hex().as_deref(), Some("#ff00aabb"));
+    }
+
+    #[test]
+    fn parses_rrggbb() {
+        let c: Color = "#1a2b3c".parse().unwrap();
+        assert_eq!(c.to_hex().as_deref(), Some("#1a2b3c"));
+    }
+
+    #[test]
+    fn parses_aarrggbb() {
+        let c: Color = "#801a2b3c".parse().unwrap();
+        assert_eq!(c.to_hex().as_deref(), Some("#801a2b3c"));
+    }
+
+    #[test]
+    fn rejects_bad_length() {
+        assert!("#12345".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn rejects_non_hex_digits() {
+        assert!("#zzzzzz".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn empty_string_is_no_color() {
+        let c: Color = "".parse().unwrap();
+        assert_eq!(c.to_hex(), None);
+    }
+
+    #[test]
+    fn parses_palette_index() {
+        let c: Color = "208".parse().unwrap();
+        assert_eq!(c.to_hex().as_deref(), Some("#ff8700"));
+    }
+
+    #[test]
+    fn rejects_out_of_range_index() {
+        assert!("256".parse::<Color>().is_err());
+    }
+
+    #[test]
+    fn xterm_256_base16_spot_checks() {
+        assert_eq!(Color::xterm_256_hex(0), "#000000");
+        assert_eq!(Color::xterm_256_hex(15), "#ffffff");
+    }
+
+    #[test]
+    fn xterm_256_grayscale_ramp_spot_checks() {
+        assert_eq!(Color::xterm_256_hex(232), "#080808");
+        assert_eq!(Color::xterm_256_hex(255), "#eeeeee");
+    }
+
+    #[test]
+    fn lemonbar_draws_fg_bg_underline() {
+        let fg: Color = "#ff0000".parse().unwrap();
+        let bg: Color = "#00ff00".parse().unwrap();
+        let underline: Color = "#0000ff".parse().unwrap();
+        let style = Style {
+            fg: &fg,
+            bg: &bg,
+            underline: &underline,
+        };
+
+        let out = style.draw("name", Output::Lemonbar, true).to_string();
+
+        assert_eq!(
+            out,
+            "%{F#ff0000}%{B#00ff00}%{+u}%{U#0000ff}name%{-u}%{B-}%{F-}"
+        );
+    }
+
+    #[test]
+    fn ansi_draws_fg_bg_underline_truecolor() {
+        let fg: Color = "#ff0000".parse().unwrap();
+        let bg: Color = "#00ff00".parse().unwrap();
+        let underline: Color = "#0000ff".parse().unwrap();
+        let style = Style {
+            fg: &fg,
+            bg: &bg,
+            underline: &underline,
+        };
+
+        let out = style.draw("name", Output::Ansi, true).to_string();
+
+        assert_eq!(
+            out,
+            "\x1b[38;2;255;0;0m\x1b[48;2;0;255;0m\x1b[4m\x1b[58;2;0;0;255mname\x1b[59m\x1b[24m\x1b[49m\x1b[39m"
+        );
+    }
+
+    #[test]
+    fn ansi_draws_palette_index_as_256_color() {
+        let fg: Color = "208".parse().unwrap();
+        let none: Color = "".parse().unwrap();
+        let style = Style {
+            fg: &fg,
+            bg: &none,
+            underline: &none,
+        };
+
+        let out = style.draw("name", Output::Ansi, true).to_string();
+
+        assert_eq!(out, "\x1b[38;5;208mname\x1b[39m");
+    }
+
+    #[test]
+    fn colorize_false_emits_bare_element() {
+        let fg: Color = "#ff0000".parse().unwrap();
+        let none: Color = "".parse().unwrap();
+        let style = Style {
+            fg: &fg,
+            bg: &none,
+            underline: &none,
+        };
+
+        let lemonbar_out = style.draw("name", Output::Lemonbar, false).to_string();
+        let ansi_out = style.draw("name", Output::Ansi, false).to_string();
+
+        assert_eq!(lemonbar_out, "name");
+        assert_eq!(ansi_out, "name");
+    }
+}